@@ -7,45 +7,247 @@
 //!
 //! You can create a `ThreadLocalRepo` by calling `ThreadLocalRepo::new`, or by using the extension
 //! trait `tlrepo::RepositoryExt` to call `.thread_local()` on an existing `git2::Repository`.
+//!
+//! For async executors, where a task can migrate between worker threads across `.await` points,
+//! see `RepoScope` instead (requires the `tokio` feature).
 #![deny(missing_docs)]
 use std::path::PathBuf;
+use std::sync::Arc;
 
 use git2::Repository;
 use thread_local::ThreadLocal;
 
+#[cfg(feature = "tokio")]
+mod task_local;
+#[cfg(feature = "tokio")]
+pub use task_local::{with_repo, RepoScope};
+
+mod trust;
+pub use trust::Error;
+
+/// The type of closure used to open a `git2::Repository` on each thread.
+///
+/// This is reference-counted, rather than uniquely owned, so that `ThreadLocalRepo::as_sync` can
+/// share it with a `SyncRepo` without re-boxing it.
+type Opener = Arc<dyn Fn() -> Result<Repository, git2::Error> + Send + Sync>;
+
+fn open_checked(
+    opener: impl FnOnce() -> Result<Repository, git2::Error>,
+    allow_untrusted: bool,
+    trusted_paths: &[PathBuf],
+) -> Result<Repository, Error> {
+    let repo = opener()?;
+    if !allow_untrusted {
+        let trusted =
+            trust::is_trusted(trusted_paths, &repo)? || trust::is_owned_by_current_user(repo.path())?;
+        if !trusted {
+            return Err(Error::Untrusted(repo.path().to_path_buf()));
+        }
+    }
+    Ok(repo)
+}
+
 /// An object providing a thread-local copy of a `git2::Repository` for each thread.
 pub struct ThreadLocalRepo {
     tl: ThreadLocal<Repository>,
-    path: PathBuf,
+    opener: Opener,
+    allow_untrusted: bool,
+    trusted_paths: Vec<PathBuf>,
 }
 
 impl ThreadLocalRepo {
-    /// Create a `ThreadLocalRepo` that opens the repository at the specified path on each thread.
+    /// Create a `ThreadLocalRepo` that opens the repository at the specified path on each thread,
+    /// via `Repository::open`.
+    ///
+    /// To open bare repositories, discover a repository from a subdirectory, honor ceiling
+    /// directories, or pass extended open flags, use `with_opener` instead.
     pub fn new(path: PathBuf) -> Self {
+        Self::with_opener(move || Repository::open(&path))
+    }
+
+    /// Create a `ThreadLocalRepo` that opens each thread's `Repository` by calling the given
+    /// closure, instead of the default `Repository::open`.
+    ///
+    /// This is the hook to use `Repository::open_ext`, `Repository::open_bare`,
+    /// `Repository::open_from_env`, or any other way of constructing a `Repository`, and to run
+    /// per-open tuning (such as `repo.odb()` cache configuration) right after each open.
+    pub fn with_opener(
+        opener: impl Fn() -> Result<Repository, git2::Error> + Send + Sync + 'static,
+    ) -> Self {
         Self {
-            path,
             tl: ThreadLocal::new(),
+            opener: Arc::new(opener),
+            allow_untrusted: true,
+            trusted_paths: Vec::new(),
         }
     }
 
-    /// Get the `git2::Repository` for this thread. Returns an error if the open fails.
+    /// Control whether repositories whose git directory isn't owned by the current user may be
+    /// opened.
+    ///
+    /// When a process opens a repository it doesn't own, that repository's git config can point
+    /// at executables (hooks, `core.fsmonitor`, filters) that then run with the current user's
+    /// privileges. By default (`allow_untrusted(true)`, the default), `ThreadLocalRepo` doesn't
+    /// check ownership, matching `git2::Repository::open`. Pass `false` to check the git
+    /// directory's ownership, and fail with `Error::Untrusted` instead of keeping a repository
+    /// open for someone else's directory. See also `trust_path`, to allow specific paths
+    /// regardless of ownership.
+    ///
+    /// The check runs on the `Repository` returned by the opener, not on a path supplied up
+    /// front: with a custom opener (see `with_opener`) the resolved git directory, e.g. from
+    /// `Repository::open_ext` discovery, isn't known until after the open completes. This means
+    /// the repository's config is already loaded by the time an untrusted repository is rejected;
+    /// this option is a way to stop using a repository you shouldn't have opened, not a way to
+    /// avoid opening it in the first place.
+    ///
+    /// Adding this option changed the error type of `get`/`get_uncached` from `git2::Error` to
+    /// `Error` for every `ThreadLocalRepo`, regardless of whether this method is ever called or
+    /// trust checking is enabled. This is an intentional, breaking API change: callers matching
+    /// on the old `git2::Error` return type need to switch to `Error` (which has a `Git(_)`
+    /// variant wrapping the original error).
+    pub fn allow_untrusted(mut self, allow: bool) -> Self {
+        self.allow_untrusted = allow;
+        self
+    }
+
+    /// Trust a specific repository regardless of its ownership.
+    ///
+    /// `path` may be either the repository's working directory (the path passed to `new`) or its
+    /// git directory (the value `Repository::path` returns); both are canonicalized before
+    /// comparison, so a relative path, a symlink, or a missing/extra trailing separator all still
+    /// match.
+    ///
+    /// Only meaningful together with `allow_untrusted(false)`.
+    pub fn trust_path(mut self, path: PathBuf) -> Self {
+        self.trusted_paths.push(path);
+        self
+    }
+
+    /// Get the `git2::Repository` for this thread. Returns an error if the open fails, or if the
+    /// repository isn't trusted (see `allow_untrusted`).
     ///
     /// Note that the cache of thread-local objects never gets pruned. If you're running on a
     /// long-running thread or a thread pool, call this method. If you're running on a short-lived
     /// thread, call `get_uncached` instead.
-    pub fn get(&self) -> Result<&Repository, git2::Error> {
-        self.tl.get_or_try(|| Repository::open(&self.path))
+    pub fn get(&self) -> Result<&Repository, Error> {
+        self.tl.get_or_try(|| self.open_checked())
     }
 
     /// Get a new `git2::Repository`, and don't save it in the thread-local cache. Returns an error
-    /// if the open fails.
+    /// if the open fails, or if the repository isn't trusted (see `allow_untrusted`).
     ///
     /// The cache of thread-local objects never gets pruned. If, over the lifetime of your process,
     /// you run an unbounded number of threads that call `get` and subsequently exit, the
     /// thread-local cache will grow without bound. In such threads, use `get_uncached` to open a
     /// repository that won't get cached.
-    pub fn get_uncached(&self) -> Result<Repository, git2::Error> {
-        Repository::open(&self.path)
+    pub fn get_uncached(&self) -> Result<Repository, Error> {
+        self.open_checked()
+    }
+
+    fn open_checked(&self) -> Result<Repository, Error> {
+        open_checked(|| (self.opener)(), self.allow_untrusted, &self.trusted_paths)
+    }
+
+    /// Convert this `ThreadLocalRepo` into an owned, cheaply-cloneable `SyncRepo`, consuming it.
+    ///
+    /// This carries the same opener and trust settings, but discards the current thread's cached
+    /// `Repository` along with the rest of the cache; each clone of the result opens its own on
+    /// first `get`.
+    pub fn into_sync(self) -> SyncRepo {
+        SyncRepo {
+            opener: self.opener,
+            allow_untrusted: self.allow_untrusted,
+            trusted_paths: self.trusted_paths.into(),
+            tl: ThreadLocal::new(),
+        }
+    }
+
+    /// Create an owned, cheaply-cloneable `SyncRepo` that shares this `ThreadLocalRepo`'s opener
+    /// and trust settings, without consuming or borrowing it.
+    ///
+    /// Use this to capture repository access by value into a spawned closure or `move` task,
+    /// while keeping the original `ThreadLocalRepo` around.
+    pub fn as_sync(&self) -> SyncRepo {
+        SyncRepo {
+            opener: Arc::clone(&self.opener),
+            allow_untrusted: self.allow_untrusted,
+            trusted_paths: self.trusted_paths.clone().into(),
+            tl: ThreadLocal::new(),
+        }
+    }
+
+    /// Remove all cached `Repository` handles, across every thread.
+    ///
+    /// This drops each thread's cached handle, releasing its file descriptors and memory maps
+    /// immediately, rather than when the `ThreadLocalRepo` itself is dropped (a thread exiting
+    /// doesn't release its cached handle). Use this to bound the
+    /// cache's memory use on a long-running thread pool that churns through many threads.
+    pub fn clear(&mut self) {
+        self.tl.clear();
+    }
+
+    /// Return the number of threads that currently have a cached `Repository`.
+    ///
+    /// Takes `&mut self`: `git2::Repository` is `Send` but not `Sync`, and `ThreadLocal::iter`
+    /// (which hands out `&T` to values created on other threads) requires `T: Sync`, so counting
+    /// has to go through `iter_mut` like the rest of this crate's maintenance methods.
+    pub fn len(&mut self) -> usize {
+        self.tl.iter_mut().count()
+    }
+
+    /// Return true if no thread currently has a cached `Repository`.
+    pub fn is_empty(&mut self) -> bool {
+        self.len() == 0
+    }
+
+    /// Iterate over every thread's cached `Repository`, to run maintenance (such as
+    /// `repo.cleanup_state()` or reloading config) across the whole pool at once.
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Repository> {
+        self.tl.iter_mut()
+    }
+}
+
+/// An owned, cheaply-cloneable handle to a repository, produced by `ThreadLocalRepo::into_sync`
+/// or `ThreadLocalRepo::as_sync`.
+///
+/// Unlike `ThreadLocalRepo`, which is normally shared by reference, `SyncRepo` is `Clone + Send +
+/// Sync` and can be captured by value into spawned closures and `move` tasks. Each clone lazily
+/// opens and caches its own thread-local `Repository` on first `get`, exactly like
+/// `ThreadLocalRepo`.
+pub struct SyncRepo {
+    opener: Opener,
+    allow_untrusted: bool,
+    trusted_paths: Arc<[PathBuf]>,
+    tl: ThreadLocal<Repository>,
+}
+
+impl Clone for SyncRepo {
+    fn clone(&self) -> Self {
+        Self {
+            opener: Arc::clone(&self.opener),
+            allow_untrusted: self.allow_untrusted,
+            trusted_paths: Arc::clone(&self.trusted_paths),
+            tl: ThreadLocal::new(),
+        }
+    }
+}
+
+impl SyncRepo {
+    /// Get the `git2::Repository` for this thread. Returns an error if the open fails, or if the
+    /// repository isn't trusted (see `ThreadLocalRepo::allow_untrusted`).
+    ///
+    /// As with `ThreadLocalRepo::get`, the cache of thread-local objects never gets pruned.
+    pub fn get(&self) -> Result<&Repository, Error> {
+        self.tl.get_or_try(|| {
+            open_checked(|| (self.opener)(), self.allow_untrusted, &self.trusted_paths)
+        })
+    }
+
+    /// Get a new `git2::Repository`, and don't save it in the thread-local cache. Returns an
+    /// error if the open fails, or if the repository isn't trusted (see
+    /// `ThreadLocalRepo::allow_untrusted`).
+    pub fn get_uncached(&self) -> Result<Repository, Error> {
+        open_checked(|| (self.opener)(), self.allow_untrusted, &self.trusted_paths)
     }
 }
 
@@ -58,6 +260,7 @@ pub trait RepositoryExt {
 
 impl RepositoryExt for Repository {
     fn thread_local(&self) -> ThreadLocalRepo {
-        ThreadLocalRepo::new(self.path().to_path_buf())
+        let path = self.path().to_path_buf();
+        ThreadLocalRepo::with_opener(move || Repository::open(&path))
     }
 }
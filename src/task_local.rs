@@ -0,0 +1,90 @@
+//! Task-local repository handles, for async executors where a task may be polled on a different
+//! worker thread across `.await` points.
+//!
+//! `ThreadLocalRepo` caches by OS thread, which doesn't work for a task that migrates between
+//! threads: it could be handed back a `Repository` opened for a thread it's no longer on, and in
+//! any case a `&Repository` can't be held across `.await` since `Repository` isn't `Sync`.
+//! `RepoScope` instead opens the repository once per task and makes it available only to
+//! synchronous closures via `with_repo`.
+
+use std::future::Future;
+use std::path::PathBuf;
+
+use git2::Repository;
+
+use crate::{open_checked, Error};
+
+tokio::task_local! {
+    static REPO: Repository;
+}
+
+/// Opens a repository once and installs it into a task-local slot for the duration of a future.
+pub struct RepoScope {
+    opener: Box<dyn FnOnce() -> Result<Repository, git2::Error> + Send>,
+    allow_untrusted: bool,
+    trusted_paths: Vec<PathBuf>,
+}
+
+impl RepoScope {
+    /// Create a `RepoScope` that opens the repository at the specified path, via
+    /// `Repository::open`.
+    pub fn new(path: PathBuf) -> Self {
+        Self::with_opener(move || Repository::open(&path))
+    }
+
+    /// Create a `RepoScope` that opens the repository by calling the given closure, instead of
+    /// the default `Repository::open`.
+    pub fn with_opener(
+        opener: impl FnOnce() -> Result<Repository, git2::Error> + Send + 'static,
+    ) -> Self {
+        Self {
+            opener: Box::new(opener),
+            allow_untrusted: true,
+            trusted_paths: Vec::new(),
+        }
+    }
+
+    /// Control whether a repository whose git directory isn't owned by the current user may be
+    /// opened, exactly like `ThreadLocalRepo::allow_untrusted`.
+    pub fn allow_untrusted(mut self, allow: bool) -> Self {
+        self.allow_untrusted = allow;
+        self
+    }
+
+    /// Trust a specific repository regardless of its ownership, exactly like
+    /// `ThreadLocalRepo::trust_path`.
+    pub fn trust_path(mut self, path: PathBuf) -> Self {
+        self.trusted_paths.push(path);
+        self
+    }
+
+    /// Open the repository and run `future` with it installed as the task-local repository for
+    /// the duration of the future.
+    ///
+    /// Like `ThreadLocalRepo::get`, this enforces the trust policy set by `allow_untrusted` and
+    /// `trust_path` before handing back the repository.
+    ///
+    /// Nested calls to `with_repo` from within `future` reuse the already-opened handle instead
+    /// of reopening. This only holds for futures `.await`ed directly within `future`'s own task:
+    /// tokio task-locals aren't inherited by `tokio::spawn`ed tasks, so calling `with_repo` from
+    /// inside a task spawned by `future` will panic.
+    pub async fn scope<F>(self, future: F) -> Result<F::Output, Error>
+    where
+        F: Future,
+    {
+        let repo = open_checked(self.opener, self.allow_untrusted, &self.trusted_paths)?;
+        Ok(REPO.scope(repo, future).await)
+    }
+}
+
+/// Run `f` with the repository installed by the enclosing `RepoScope::scope`.
+///
+/// # Panics
+///
+/// Panics if called outside of a `RepoScope::scope` future.
+pub fn with_repo<F, R>(f: F) -> R
+where
+    F: FnOnce(&Repository) -> R,
+{
+    REPO.with(f)
+}
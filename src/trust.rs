@@ -0,0 +1,197 @@
+//! Ownership checks, to reject a repository owned by someone other than the current user before
+//! it's handed out, so that config-derived executables such as hooks or `core.fsmonitor` from
+//! that repository don't get run through it. The check runs on an already-opened `Repository`,
+//! so the repository's config has already been read by the time it's rejected.
+
+use std::path::{Path, PathBuf};
+
+use git2::Repository;
+
+/// An error from `ThreadLocalRepo`.
+#[derive(Debug)]
+pub enum Error {
+    /// The repository open itself failed.
+    Git(git2::Error),
+    /// Checking the git directory's ownership failed.
+    Io(std::io::Error),
+    /// The repository's git directory is not owned by the current user, and hasn't been marked
+    /// trusted.
+    Untrusted(PathBuf),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Git(e) => e.fmt(f),
+            Error::Io(e) => e.fmt(f),
+            Error::Untrusted(path) => {
+                write!(f, "repository at {} is not owned by the current user", path.display())
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Git(e) => Some(e),
+            Error::Io(e) => Some(e),
+            Error::Untrusted(_) => None,
+        }
+    }
+}
+
+impl From<git2::Error> for Error {
+    fn from(e: git2::Error) -> Self {
+        Error::Git(e)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+/// Return whether `repo` matches one of `trusted_paths`.
+///
+/// `trusted_paths` entries may be either the repository's working directory or its git directory
+/// (i.e. either the path originally passed to `ThreadLocalRepo::new`, or the value
+/// `Repository::path` returns), in any form `std::fs::canonicalize` would normalize to the same
+/// place: relative, symlinked, or with or without a trailing separator. Both sides are
+/// canonicalized before comparison, so none of that needs to match exactly.
+pub(crate) fn is_trusted(trusted_paths: &[PathBuf], repo: &Repository) -> std::io::Result<bool> {
+    let git_dir = std::fs::canonicalize(repo.path())?;
+    let work_dir = repo.workdir().map(std::fs::canonicalize).transpose()?;
+    for path in trusted_paths {
+        let Ok(canon) = std::fs::canonicalize(path) else {
+            continue;
+        };
+        if canon == git_dir || Some(&canon) == work_dir.as_ref() {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Return whether `path` is owned by the current user.
+#[cfg(unix)]
+pub(crate) fn is_owned_by_current_user(path: &Path) -> std::io::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+    let meta = std::fs::metadata(path)?;
+    Ok(meta.uid() == unsafe { libc::geteuid() })
+}
+
+/// Return whether `path` is owned by the current user.
+///
+/// Compares the directory's owner SID (read via `GetNamedSecurityInfoW`) against the current
+/// process token's user SID (`GetTokenInformation` with `TokenUser`).
+#[cfg(windows)]
+pub(crate) fn is_owned_by_current_user(path: &Path) -> std::io::Result<bool> {
+    use std::os::windows::ffi::OsStrExt;
+    use windows_sys::Win32::Foundation::{CloseHandle, LocalFree, HANDLE};
+    use windows_sys::Win32::Security::Authorization::{
+        GetNamedSecurityInfoW, SE_FILE_OBJECT,
+    };
+    use windows_sys::Win32::Security::{
+        EqualSid, GetTokenInformation, TokenUser, OWNER_SECURITY_INFORMATION, PSID,
+        TOKEN_QUERY, TOKEN_USER,
+    };
+    use windows_sys::Win32::System::Threading::{GetCurrentProcess, OpenProcessToken};
+
+    let wide: Vec<u16> = path.as_os_str().encode_wide().chain(std::iter::once(0)).collect();
+
+    let mut owner_sid: PSID = std::ptr::null_mut();
+    let mut descriptor = std::ptr::null_mut();
+    let status = unsafe {
+        GetNamedSecurityInfoW(
+            wide.as_ptr(),
+            SE_FILE_OBJECT,
+            OWNER_SECURITY_INFORMATION,
+            &mut owner_sid,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            &mut descriptor,
+        )
+    };
+    if status != 0 {
+        return Err(std::io::Error::from_raw_os_error(status as i32));
+    }
+
+    let mut token: HANDLE = std::ptr::null_mut();
+    unsafe {
+        if OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, &mut token) == 0 {
+            LocalFree(descriptor as _);
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+
+    let mut buf = [0u8; 512];
+    let mut len = 0u32;
+    let owns = unsafe {
+        let ok = GetTokenInformation(
+            token,
+            TokenUser,
+            buf.as_mut_ptr() as _,
+            buf.len() as u32,
+            &mut len,
+        );
+        CloseHandle(token);
+        if ok == 0 {
+            LocalFree(descriptor as _);
+            return Err(std::io::Error::last_os_error());
+        }
+        let token_user = &*(buf.as_ptr() as *const TOKEN_USER);
+        EqualSid(owner_sid, token_user.User.Sid) != 0
+    };
+
+    unsafe {
+        LocalFree(descriptor as _);
+    }
+
+    Ok(owns)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn temp_repo_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("tlrepo-trust-test-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn a_freshly_created_dir_is_owned_by_the_current_user() {
+        let dir = temp_repo_dir("owned");
+        assert!(is_owned_by_current_user(&dir).unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn trust_path_matches_regardless_of_trailing_slash_or_which_directory_is_given() {
+        let dir = temp_repo_dir("trust-path");
+        let repo = Repository::init(&dir).unwrap();
+        let git_dir = repo.path().to_path_buf();
+
+        // The exact value `Repository::path` returns.
+        assert!(is_trusted(&[git_dir.clone()], &repo).unwrap());
+
+        // The same git directory, without whatever trailing separator libgit2 appended.
+        let git_dir_no_trailing_slash = PathBuf::from(git_dir.to_string_lossy().trim_end_matches('/'));
+        assert!(is_trusted(&[git_dir_no_trailing_slash], &repo).unwrap());
+
+        // The repository's working directory, rather than its `.git` directory.
+        assert!(is_trusted(&[dir.clone()], &repo).unwrap());
+
+        // An unrelated directory doesn't match.
+        let other = temp_repo_dir("trust-path-other");
+        assert!(!is_trusted(&[other.clone()], &repo).unwrap());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        std::fs::remove_dir_all(&other).unwrap();
+    }
+}